@@ -0,0 +1,148 @@
+//! Predicate-based event filtering for the `NooBaaSource` watch.
+//!
+//! Sits between the watcher and the reconcile trigger (in the spirit of
+//! `kube::runtime::WatchStreamExt`) and drops `Applied` events that don't
+//! change anything we care about, so that status-only self-writes (like the
+//! `patch_status` call in `reconcile`) don't cause reconcile storms.
+
+use crate::manager::NooBaaSource;
+use futures::{Stream, StreamExt};
+use kube::{runtime::watcher, ResourceExt};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+/// Stable hash over the fields of a `NooBaaSource` that matter for
+/// reconciliation: its spec plus labels/annotations. Anything else
+/// (resourceVersion, status, managedFields, ...) is deliberately excluded.
+fn content_hash(obj: &NooBaaSource) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", obj.spec).hash(&mut hasher);
+    let mut labels: Vec<_> = obj.labels().iter().collect();
+    labels.sort();
+    labels.hash(&mut hasher);
+    let mut annotations: Vec<_> = obj.annotations().iter().collect();
+    annotations.sort();
+    annotations.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Drop `Applied` events whose [`content_hash`] matches the last seen value
+/// for that object's uid. `Deleted` and `Restarted` events (initial applies
+/// after a re-list) are always forwarded; a `Deleted` event also forgets the
+/// uid's last-seen hash so `seen` doesn't grow without bound over the
+/// controller's lifetime under create/delete churn.
+pub fn dedup_events(
+    stream: impl Stream<Item = watcher::Result<watcher::Event<NooBaaSource>>> + Send + 'static,
+) -> impl Stream<Item = watcher::Result<watcher::Event<NooBaaSource>>> + Send + 'static {
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    stream.filter_map(move |res| {
+        let out = match res {
+            Ok(watcher::Event::Applied(obj)) => {
+                let uid = obj.uid().unwrap_or_default();
+                let hash = content_hash(&obj);
+                if seen.insert(uid, hash) == Some(hash) {
+                    None
+                } else {
+                    Some(Ok(watcher::Event::Applied(obj)))
+                }
+            }
+            Ok(watcher::Event::Deleted(obj)) => {
+                seen.remove(&obj.uid().unwrap_or_default());
+                Some(Ok(watcher::Event::Deleted(obj)))
+            }
+            other => Some(other),
+        };
+        futures::future::ready(out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::{CloudEventOverrides, Destination, NooBaaSourceSpec, Source};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use std::collections::BTreeMap;
+
+    fn source(uid: &str, info: &str, labels: BTreeMap<String, String>) -> NooBaaSource {
+        let spec = NooBaaSourceSpec {
+            name: "src".into(),
+            source: Source {
+                rpc_url: "http://noobaa".into(),
+                rpc_secret: "creds".into(),
+                bucket: info.into(),
+            },
+            sink: Destination { reference: None, uri: None },
+            ce_overrides: None::<CloudEventOverrides>,
+        };
+        let mut obj = NooBaaSource::new("src", spec);
+        obj.metadata = ObjectMeta {
+            uid: Some(uid.into()),
+            labels: Some(labels),
+            ..Default::default()
+        };
+        obj
+    }
+
+    async fn run(events: Vec<watcher::Result<watcher::Event<NooBaaSource>>>) -> Vec<watcher::Event<NooBaaSource>> {
+        dedup_events(futures::stream::iter(events))
+            .filter_map(|r| async move { r.ok() })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn drops_applied_with_unchanged_hash() {
+        let obj = source("u1", "bucket-a", BTreeMap::new());
+        let out = run(vec![
+            Ok(watcher::Event::Applied(obj.clone())),
+            Ok(watcher::Event::Applied(obj)),
+        ])
+        .await;
+        assert_eq!(out.len(), 1, "second identical Applied should be dropped");
+    }
+
+    #[tokio::test]
+    async fn forwards_applied_with_changed_spec() {
+        let first = source("u1", "bucket-a", BTreeMap::new());
+        let second = source("u1", "bucket-b", BTreeMap::new());
+        let out = run(vec![Ok(watcher::Event::Applied(first)), Ok(watcher::Event::Applied(second))]).await;
+        assert_eq!(out.len(), 2, "a spec change must be forwarded");
+    }
+
+    #[tokio::test]
+    async fn forwards_applied_with_changed_labels() {
+        let mut labels = BTreeMap::new();
+        labels.insert("team".to_string(), "a".to_string());
+        let first = source("u1", "bucket-a", BTreeMap::new());
+        let second = source("u1", "bucket-a", labels);
+        let out = run(vec![Ok(watcher::Event::Applied(first)), Ok(watcher::Event::Applied(second))]).await;
+        assert_eq!(out.len(), 2, "a label change must be forwarded");
+    }
+
+    #[tokio::test]
+    async fn always_forwards_deleted_and_restarted() {
+        let obj = source("u1", "bucket-a", BTreeMap::new());
+        let out = run(vec![
+            Ok(watcher::Event::Applied(obj.clone())),
+            Ok(watcher::Event::Applied(obj.clone())), // would be dropped on its own
+            Ok(watcher::Event::Deleted(obj.clone())),
+            Ok(watcher::Event::Restarted(vec![obj])),
+        ])
+        .await;
+        assert_eq!(out.len(), 3, "Deleted and Restarted are never deduped");
+    }
+
+    #[tokio::test]
+    async fn forgets_hash_after_delete() {
+        let obj = source("u1", "bucket-a", BTreeMap::new());
+        let out = run(vec![
+            Ok(watcher::Event::Applied(obj.clone())),
+            Ok(watcher::Event::Deleted(obj.clone())),
+            Ok(watcher::Event::Applied(obj)), // re-created with identical content
+        ])
+        .await;
+        assert_eq!(out.len(), 3, "a re-apply after Deleted must not be treated as unchanged");
+    }
+}
@@ -1,24 +1,37 @@
-use crate::{telemetry, Error, Result};
+use crate::{dedup, sink, telemetry, Error, Result};
 use chrono::prelude::*;
-use futures::{future::BoxFuture, FutureExt, StreamExt};
-use k8s_openapi::api::core::v1::ObjectReference;
+use futures::{future::BoxFuture, FutureExt, StreamExt, TryStreamExt};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::ObjectReference, core::v1::Secret};
 use kube::{
     api::{Api, ListParams, Patch, PatchParams, ResourceExt},
     client::Client,
     runtime::{
-        controller::{Context, Controller, ReconcilerAction},
+        controller::{Context, Controller, ReconcileReason, ReconcileRequest, ReconcilerAction},
         events::{Event, EventType, Recorder, Reporter},
+        finalizer::{self, Event as FinalizerEvent},
+        reflector,
+        reflector::ObjectRef,
+        watcher,
+        WatchStreamExt,
     },
     CustomResource, Resource,
 };
-use prometheus::{
-    default_registry, proto::MetricFamily, register_histogram_vec, register_int_counter, HistogramOpts,
-    HistogramVec, IntCounter,
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{counter::Counter, exemplar::HistogramWithExemplars},
+    registry::Registry,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::{
     sync::RwLock,
     time::{Duration, Instant},
@@ -30,14 +43,14 @@ use tracing::{debug, error, event, field, info, instrument, trace, warn, Level,
 pub struct Source {
     // URL of the NooNaa management RPC service
     #[serde(rename = "rpcUrl")]
-    rpc_url: String,
+    pub(crate) rpc_url: String,
 
     // cecret name containing credentials for the NooNaa management RPC service
     #[serde(rename = "rpcSecret")]
-    rpc_secret: String,
+    pub(crate) rpc_secret: String,
 
     // Bucket name
-    bucket: String,
+    pub(crate) bucket: String,
 }
 // KReference contains enough information to refer to Sink
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
@@ -45,24 +58,24 @@ pub struct Source {
 pub struct KReference {
     // kind of the referent.
     // More info: https://git.k8s.io/community/contributors/devel/sig-architecture/api-conventions.md#types-kinds
-    kind: String,
+    pub(crate) kind: String,
 
     // namespace of the referent.
     // More info: https://kubernetes.io/docs/concepts/overview/working-with-objects/namespaces/
     // This is optional field, it gets defaulted to the object holding it if left out.
-    namespace: Option<String>,
+    pub(crate) namespace: Option<String>,
 
     // name of the referent.
     // More info: https://kubernetes.io/docs/concepts/overview/working-with-objects/names/#names
-    name: String,
+    pub(crate) name: String,
 
     // api version of the referent.
     #[serde(rename = "apiVersion")]
-    api_version: Option<String>,
+    pub(crate) api_version: Option<String>,
 
     // group of the API, without the version of the group. This can be used as an alternative to the APIVersion, and then resolved using ResolveGroup.
     // Note: This API is EXPERIMENTAL and might break anytime. For more details: https://github.com/knative/eventing/issues/5086
-    group: Option<String>,
+    pub(crate) group: Option<String>,
 }
 
 // Destination represents a target of an invocation over HTTP.
@@ -70,10 +83,10 @@ pub struct KReference {
 pub struct Destination {
     // ref points to an Addressable.
     #[serde(rename = "ref")]
-    reference: Option<KReference>,
+    pub(crate) reference: Option<KReference>,
 
     // uri can be an absolute URL(non-empty scheme and non-empty host) pointing to the target or a relative URI. Relative URIs will be resolved using the base URI retrieved from Ref.
-    uri: Option<String>,
+    pub(crate) uri: Option<String>,
 }
 
 // CloudEventOverrides defines arguments for a Source that control the output
@@ -91,11 +104,11 @@ pub struct CloudEventOverrides {
 #[kube(kind = "NooBaaSource", group = "knative.dev", version = "v1", namespaced)]
 #[kube(status = "NooBaaSourceStatus")]
 pub struct NooBaaSourceSpec {
-    name: String,
-    source: Source,
-    sink: Destination,
+    pub(crate) name: String,
+    pub(crate) source: Source,
+    pub(crate) sink: Destination,
     #[serde(rename = "ceOverrides")]
-    ce_overrides: Option<CloudEventOverrides>,
+    pub(crate) ce_overrides: Option<CloudEventOverrides>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
@@ -104,6 +117,46 @@ pub struct NooBaaSourceStatus {
     //last_updated: Option<DateTime<Utc>>,
 }
 
+/// Name of the child `Deployment` that bridges the NooBaa bucket to the sink
+fn bridge_name(noobaa_source: &NooBaaSource) -> String {
+    format!("{}-bridge", ResourceExt::name(noobaa_source))
+}
+
+/// Desired state of the child `Deployment`, owned by `noobaa_source`
+///
+/// The owner reference is what makes the controller self-healing: the
+/// runtime watches `Deployment`s via `Controller::owns`, reads this back out
+/// of `metadata.ownerReferences` and re-enqueues `noobaa_source` whenever the
+/// child drifts, and Kubernetes garbage-collects the child on deletion.
+fn bridge_deployment(noobaa_source: &NooBaaSource, sink_url: &str) -> serde_json::Value {
+    let owner = noobaa_source
+        .controller_owner_ref(&())
+        .expect("NooBaaSource from the API server always has a name and uid");
+    let name = bridge_name(noobaa_source);
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {
+            "name": name,
+            "ownerReferences": [owner],
+        },
+        "spec": {
+            "replicas": 1,
+            "selector": { "matchLabels": { "app": name } },
+            "template": {
+                "metadata": { "labels": { "app": name } },
+                "spec": {
+                    "containers": [{
+                        "name": "bridge",
+                        "image": "ghcr.io/baum/noobaa-bridge:latest",
+                        "env": [{ "name": "SINK_URL", "value": sink_url }],
+                    }],
+                },
+            },
+        },
+    })
+}
+
 // Context for our reconciler
 #[derive(Clone)]
 struct Data {
@@ -113,21 +166,132 @@ struct Data {
     state: Arc<RwLock<State>>,
     /// Various prometheus metrics
     metrics: Metrics,
+    /// Count of reconciles currently in flight, so a graceful shutdown can
+    /// wait for it to hit zero instead of cancelling them mid-await
+    in_flight: Arc<AtomicUsize>,
 }
 
-#[instrument(skip(ctx), fields(trace_id))]
+/// RAII handle that decrements `Data::in_flight` on drop, covering early
+/// returns (including the `?`s in `apply` and `cleanup`) as well as the
+/// normal path
+struct InFlightGuard(Arc<AtomicUsize>);
+impl InFlightGuard {
+    fn enter(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(counter)
+    }
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Finalizer stamped onto every `NooBaaSource` so deletion is deferred until
+/// its external bucket-notification subscription has been torn down
+const FINALIZER: &str = "noobaasource.knative.dev/cleanup";
+
+/// True if `err` is a Kubernetes 404 - the object is already gone
+fn is_not_found(err: &kube::Error) -> bool {
+    matches!(err, kube::Error::Api(e) if e.code == 404)
+}
+
+/// Deregister the bucket-notification subscription from the NooBaa RPC
+/// service, so deleting a `NooBaaSource` doesn't leak it behind
+///
+/// `Secret`s and the `NooBaaSource` can be deleted in either order (e.g.
+/// during namespace teardown), and the RPC subscription may already be
+/// gone by the time this runs - both cases are treated as a successful
+/// no-op so the finalizer never gets permanently stuck.
+async fn deregister_subscription(client: Client, ns: &str, source: &Source) -> Result<(), Error> {
+    let secrets: Api<Secret> = Api::namespaced(client, ns);
+    let secret = match secrets.get(&source.rpc_secret).await {
+        Ok(secret) => secret,
+        Err(e) if is_not_found(&e) => return Ok(()),
+        Err(e) => return Err(Error::KubeError(e)),
+    };
+    let token = secret
+        .data
+        .as_ref()
+        .and_then(|d| d.get("token"))
+        .map(|b| String::from_utf8_lossy(&b.0).into_owned())
+        .ok_or_else(|| Error::MissingRpcToken(source.rpc_secret.clone()))?;
+
+    let response = reqwest::Client::new()
+        .delete(format!("{}/buckets/{}/notifications", source.rpc_url, source.bucket))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(Error::RpcError)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(()); // subscription already gone
+    }
+    response.error_for_status().map_err(Error::RpcError)?;
+    Ok(())
+}
+
+/// Entry point handed to `Controller::run`
+///
+/// The actual work happens in [`reconcile_inner`], spawned onto its own
+/// task: `run()`'s output is cut short by `take_until` on shutdown (since a
+/// self-rearming `requeue_after` means the trigger stream alone never runs
+/// dry), and dropping that poll would cancel whatever reconcile is mid-await
+/// at the time. A detached `tokio::spawn` keeps running to completion
+/// either way - `InFlightGuard` is entered before the task is spawned and
+/// moved into it, so the drainer in `new_with_shutdown` still waits for it,
+/// even once nothing is left awaiting this function's own result.
 async fn reconcile(noobaa_source: Arc<NooBaaSource>, ctx: Context<Data>) -> Result<ReconcilerAction, Error> {
+    let in_flight = InFlightGuard::enter(ctx.get_ref().in_flight.clone());
+    let handle = tokio::spawn(async move {
+        let _in_flight = in_flight;
+        reconcile_inner(noobaa_source, ctx).await
+    });
+    handle.await.unwrap_or_else(|e| Err(Error::ReconcileTaskPanicked(e.to_string())))
+}
+
+#[instrument(skip(ctx), fields(trace_id))]
+async fn reconcile_inner(noobaa_source: Arc<NooBaaSource>, ctx: Context<Data>) -> Result<ReconcilerAction, Error> {
     let trace_id = telemetry::get_trace_id();
     Span::current().record("trace_id", &field::display(&trace_id));
+    ctx.get_ref().state.write().await.last_event = Utc::now();
+
+    let client = ctx.get_ref().client.clone();
+    let ns = ResourceExt::namespace(noobaa_source.as_ref()).expect("NooBaaSource is namespaced");
+    let noobaa_sources: Api<NooBaaSource> = Api::namespaced(client, &ns);
+
+    finalizer::finalizer(&noobaa_sources, FINALIZER, noobaa_source, |event| async {
+        match event {
+            FinalizerEvent::Apply(noobaa_source) => apply(noobaa_source, ctx.clone()).await,
+            FinalizerEvent::Cleanup(noobaa_source) => cleanup(noobaa_source, ctx.clone()).await,
+        }
+    })
+    .await
+    .map_err(|e| Error::FinalizerError(Box::new(e)))
+}
+
+/// Cleanup branch: deregister the external subscription before the
+/// finalizer is removed and the object is allowed to be garbage-collected
+async fn cleanup(noobaa_source: Arc<NooBaaSource>, ctx: Context<Data>) -> Result<ReconcilerAction, Error> {
+    let client = ctx.get_ref().client.clone();
+    let name = ResourceExt::name(noobaa_source.as_ref());
+    let ns = ResourceExt::namespace(noobaa_source.as_ref()).expect("NooBaaSource is namespaced");
+
+    deregister_subscription(client, &ns, &noobaa_source.spec.source).await?;
+    info!("Cleaned up NooBaaSource \"{}\" in {}", name, ns);
+
+    Ok(ReconcilerAction { requeue_after: None })
+}
+
+/// Apply branch: the original reconcile logic, run on every create/update
+async fn apply(noobaa_source: Arc<NooBaaSource>, ctx: Context<Data>) -> Result<ReconcilerAction, Error> {
     let start = Instant::now();
 
     let client = ctx.get_ref().client.clone();
-    ctx.get_ref().state.write().await.last_event = Utc::now();
     let reporter = ctx.get_ref().state.read().await.reporter.clone();
     let recorder = Recorder::new(client.clone(), reporter, noobaa_source.object_ref(&()));
     let name = ResourceExt::name(noobaa_source.as_ref());
     let ns = ResourceExt::namespace(noobaa_source.as_ref()).expect("NooBaaSource is namespaced");
-    let noobaa_sources: Api<NooBaaSource> = Api::namespaced(client, &ns);
+    let noobaa_sources: Api<NooBaaSource> = Api::namespaced(client.clone(), &ns);
 
     let new_status = Patch::Apply(json!({
         "apiVersion": "knative.dev/v1",
@@ -167,14 +331,21 @@ async fn reconcile(noobaa_source: Arc<NooBaaSource>, ctx: Context<Data>) -> Resu
             .map_err(Error::KubeError)?;
     }
 
+    let sink_url = sink::resolve_sink(client.clone(), &ns, &recorder, &noobaa_source.spec.sink).await?;
+
+    let deployments: Api<Deployment> = Api::namespaced(client, &ns);
+    let bridge = bridge_deployment(&noobaa_source, &sink_url);
+    deployments
+        .patch(&bridge_name(&noobaa_source), &ps, &Patch::Apply(&bridge))
+        .await
+        .map_err(Error::KubeError)?;
+
     let duration = start.elapsed().as_millis() as f64 / 1000.0;
-    //let ex = Exemplar::new_with_labels(duration, HashMap::from([("trace_id".to_string(), trace_id)]);
+    let trace_id = telemetry::get_trace_id();
     ctx.get_ref()
         .metrics
         .reconcile_duration
-        .with_label_values(&[])
-        .observe(duration);
-    //.observe_with_exemplar(duration, ex);
+        .observe(duration, Some(TraceExemplar { trace_id: trace_id.to_string() }));
     ctx.get_ref().metrics.handled_events.inc();
     info!("Reconciled NooBaaSource \"{}\" in {}", name, ns);
 
@@ -190,26 +361,46 @@ fn error_policy(error: &Error, _ctx: Context<Data>) -> ReconcilerAction {
     }
 }
 
-/// Metrics exposed on /metrics
+/// Exemplar labels attached to a `reconcile_duration` observation, linking a
+/// latency bucket back to the distributed trace of that specific reconcile
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TraceExemplar {
+    trace_id: String,
+}
+
+/// Metrics exposed on /metrics in OpenMetrics text format
 #[derive(Clone)]
 pub struct Metrics {
-    pub handled_events: IntCounter,
-    pub reconcile_duration: HistogramVec,
+    pub handled_events: Counter,
+    pub reconcile_duration: HistogramWithExemplars<TraceExemplar>,
 }
 impl Metrics {
-    fn new() -> Self {
-        let reconcile_histogram = register_histogram_vec!(
+    /// Builds the metrics and registers them into a fresh `Registry`
+    fn new() -> (Self, Registry) {
+        let mut registry = Registry::default();
+
+        let handled_events = Counter::default();
+        registry.register(
+            "noobaa_source_controller_handled_events",
+            "handled events",
+            handled_events.clone(),
+        );
+
+        let reconcile_duration =
+            HistogramWithExemplars::new([0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.].into_iter());
+        registry.register(
             "noobaa_source_controller_reconcile_duration_seconds",
             "The duration of reconcile to complete in seconds",
-            &[],
-            vec![0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.]
-        )
-        .unwrap();
+            reconcile_duration.clone(),
+        );
 
-        Metrics {
-            handled_events: register_int_counter!("noobaa_source_controller_handled_events", "handled events").unwrap(),
-            reconcile_duration: reconcile_histogram,
-        }
+        (
+            Metrics {
+                handled_events,
+                reconcile_duration,
+            },
+            registry,
+        )
     }
 }
 
@@ -230,11 +421,52 @@ impl State {
     }
 }
 
+/// Resolves on SIGTERM/SIGINT so rolling updates can drain us cleanly
+///
+/// Used as the default shutdown signal by [`Manager::new`]; pass your own
+/// future to [`Manager::new_with_shutdown`] to override it.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Received shutdown signal, draining in-flight reconciles");
+}
+
+/// Options controlling how the `Manager` wires up its `Controller`
+pub struct ManagerOptions {
+    /// Drop watch events that don't change `spec`/labels/annotations (see
+    /// [`dedup`]), so our own `patch_status` writes don't cause reconcile
+    /// storms. Disable this for controllers that need to see every event.
+    pub dedup_events: bool,
+}
+
+impl Default for ManagerOptions {
+    fn default() -> Self {
+        ManagerOptions { dedup_events: true }
+    }
+}
+
 /// Data owned by the Manager
 #[derive(Clone)]
 pub struct Manager {
     /// In memory state
     state: Arc<RwLock<State>>,
+    /// Registry the prometheus metrics are exposed through
+    registry: Arc<Registry>,
 }
 
 /// Example Manager that owns a Controller for NooBaaSource
@@ -242,37 +474,107 @@ impl Manager {
     /// Lifecycle initialization interface for app
     ///
     /// This returns a `Manager` that drives a `Controller` + a future to be awaited
-    /// It is up to `main` to wait for the controller stream.
+    /// It is up to `main` to wait for the controller stream. The controller
+    /// shuts down gracefully on SIGTERM/SIGINT, draining in-flight reconciles
+    /// before the returned future resolves.
     pub async fn new() -> (Self, BoxFuture<'static, ()>) {
+        Self::new_with_options(ManagerOptions::default()).await
+    }
+
+    /// Like [`Manager::new`], but with control over event filtering
+    pub async fn new_with_options(opts: ManagerOptions) -> (Self, BoxFuture<'static, ()>) {
+        Self::new_with_shutdown(opts, shutdown_signal()).await
+    }
+
+    /// Like [`Manager::new_with_options`], but with a caller-supplied
+    /// shutdown signal instead of the default SIGTERM/SIGINT handling
+    ///
+    /// The returned drainer future stops pulling new reconcile requests as
+    /// soon as `shutdown` resolves, then waits for `Data::in_flight` to drop
+    /// to zero before resolving itself - reconciles that are already
+    /// mid-await (e.g. on a Kubernetes API call) run to completion instead
+    /// of being cancelled, so a rolling update doesn't cut one off midway.
+    pub async fn new_with_shutdown(
+        opts: ManagerOptions,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> (Self, BoxFuture<'static, ()>) {
         let client = Client::try_default().await.expect("create client");
-        let metrics = Metrics::new();
+        let (metrics, registry) = Metrics::new();
         let state = Arc::new(RwLock::new(State::new()));
+        let in_flight = Arc::new(AtomicUsize::new(0));
         let context = Context::new(Data {
             client: client.clone(),
             metrics: metrics.clone(),
             state: state.clone(),
+            in_flight: in_flight.clone(),
         });
 
-        let noobaa_sources = Api::<NooBaaSource>::all(client);
+        let noobaa_sources = Api::<NooBaaSource>::all(client.clone());
         // Ensure CRD is installed before loop-watching
         let _r = noobaa_sources
             .list(&ListParams::default().limit(1))
             .await
             .expect("is the crd installed? please run: cargo run --bin crdgen | kubectl apply -f -");
 
+        // Build the reconcile trigger ourselves (rather than handing the Api
+        // straight to `Controller::new`) so we can splice the dedup filter
+        // in between the watcher and the reconcile queue.
+        let (store_reader, store_writer) = reflector::store();
+        let watch = watcher(noobaa_sources, watcher::Config::default())
+            .default_backoff()
+            .reflect(store_writer);
+        let trigger = if opts.dedup_events {
+            dedup::dedup_events(watch).applied_objects().boxed()
+        } else {
+            watch.applied_objects().boxed()
+        }
+        .map_ok(|obj| ReconcileRequest {
+            obj_ref: ObjectRef::from_obj(&obj),
+            reason: ReconcileReason::ObjectUpdated,
+        });
+
         // All good. Start controller and return its future.
-        let drainer = Controller::new(noobaa_sources, ListParams::default())
-            .run(reconcile, error_policy, context)
-            .filter_map(|x| async move { std::result::Result::ok(x) })
-            .for_each(|_| futures::future::ready(()))
-            .boxed();
+        let bridge_deployments = Api::<Deployment>::all(client);
+        let drainer = async move {
+            // `take_until` has to bound `run()`'s own output, not just the
+            // trigger: every reconcile sets `requeue_after`, so the
+            // Controller's internal scheduler keeps re-feeding itself and
+            // the stream would otherwise never run dry on its own. Cutting
+            // it off here can drop a reconcile that's mid-await, but that's
+            // fine - `reconcile` spawns the real work onto its own task, so
+            // the in-flight count below reflects it regardless.
+            Controller::for_stream(trigger, store_reader)
+                .owns(bridge_deployments, ListParams::default())
+                .run(reconcile, error_policy, context)
+                .take_until(shutdown)
+                .filter_map(|x| async move { std::result::Result::ok(x) })
+                .for_each(|_| futures::future::ready(()))
+                .await;
+
+            // `run()`'s output has been cut off, but reconciles already
+            // spawned onto their own tasks may still be running - wait for
+            // them to finish instead of leaving them to be dropped with the
+            // runtime.
+            while in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+        .boxed();
 
-        (Self { state }, drainer)
+        (
+            Self {
+                state,
+                registry: Arc::new(registry),
+            },
+            drainer,
+        )
     }
 
-    /// Metrics getter
-    pub fn metrics(&self) -> Vec<MetricFamily> {
-        default_registry().gather()
+    /// Metrics getter, encoded as OpenMetrics text (including exemplars)
+    pub fn metrics(&self) -> String {
+        let mut buf = String::new();
+        encode(&mut buf, &self.registry).expect("OpenMetrics encoding is infallible for a String sink");
+        buf
     }
 
     /// State getter
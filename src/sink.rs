@@ -0,0 +1,207 @@
+//! Resolves a `Destination` sink to a concrete delivery URL.
+//!
+//! `Destination`/`KReference` model a Knative-style sink: a `ref` to an
+//! Addressable plus an optional relative `uri`. Nothing reads `status.address`
+//! off the referent on its own, so this module does the duck-typed lookup:
+//! default the namespace, resolve the referent's `ApiResource` via discovery,
+//! fetch it, and pull `status.address.url` out of it.
+
+use crate::manager::{Destination, KReference};
+use crate::{Error, Result};
+use kube::{
+    api::{Api, DynamicObject},
+    discovery::{ApiResource, Discovery},
+    runtime::events::{Event, EventType, Recorder},
+    Client,
+};
+use serde_json::Value;
+
+/// Resolve `destination` to a concrete URL, recording a warning `Event` via
+/// `recorder` if resolution fails
+pub async fn resolve_sink(
+    client: Client,
+    default_ns: &str,
+    recorder: &Recorder,
+    destination: &Destination,
+) -> Result<String, Error> {
+    let result = try_resolve_sink(client, default_ns, destination).await;
+    if let Err(e) = &result {
+        recorder
+            .publish(Event {
+                type_: EventType::Warning,
+                reason: "SinkResolutionFailed".into(),
+                note: Some(e.to_string()),
+                action: "ResolvingSink".into(),
+                secondary: None,
+            })
+            .await
+            .map_err(Error::KubeError)?;
+    }
+    result
+}
+
+async fn try_resolve_sink(client: Client, default_ns: &str, destination: &Destination) -> Result<String, Error> {
+    match (&destination.reference, &destination.uri) {
+        (Some(reference), uri) => {
+            let base = addressable_url(client, default_ns, reference).await?;
+            match uri {
+                Some(uri) => join_uri(&base, uri),
+                None => Ok(base),
+            }
+        }
+        // No ref to an Addressable to resolve against - the uri has to
+        // stand on its own, so it must be absolute rather than relative
+        (None, Some(uri)) => absolute_uri(uri),
+        (None, None) => Err(Error::SinkNotResolvable("sink has neither a ref nor a uri".into())),
+    }
+}
+
+/// Validate that `uri` is usable as a sink on its own, with no Addressable
+/// `ref` to resolve it against
+fn absolute_uri(uri: &str) -> Result<String, Error> {
+    let parsed = reqwest::Url::parse(uri)
+        .map_err(|e| Error::SinkNotResolvable(format!("sink has no ref, and uri \"{}\" is not an absolute URL: {}", uri, e)))?;
+    if parsed.host_str().map_or(true, str::is_empty) {
+        return Err(Error::SinkNotResolvable(format!(
+            "sink has no ref, and uri \"{}\" has no host",
+            uri
+        )));
+    }
+    Ok(parsed.to_string())
+}
+
+/// Fetch the referent and read its `status.address.url` (the Addressable
+/// contract), defaulting its namespace to `default_ns` when omitted
+async fn addressable_url(client: Client, default_ns: &str, reference: &KReference) -> Result<String, Error> {
+    let ns = reference.namespace.clone().unwrap_or_else(|| default_ns.to_string());
+    let api_resource = resolve_group(&client, reference).await?;
+    let api: Api<DynamicObject> = Api::namespaced_with(client, &ns, &api_resource);
+    let referent = api.get(&reference.name).await.map_err(Error::KubeError)?;
+
+    referent
+        .data
+        .pointer("/status/address/url")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            Error::SinkNotResolvable(format!(
+                "{} \"{}\" in {} has no status.address.url",
+                reference.kind, reference.name, ns
+            ))
+        })
+}
+
+/// What to ask discovery for: the group name, plus - when `apiVersion`
+/// pinned an exact version - that version too. Kept free of `Client`/
+/// `Discovery` so it's unit-testable on its own.
+fn group_and_version(reference: &KReference) -> (String, Option<String>) {
+    if let Some(api_version) = &reference.api_version {
+        return match api_version.split_once('/') {
+            Some((group, version)) => (group.to_string(), Some(version.to_string())),
+            None => (String::new(), Some(api_version.clone())), // core group, e.g. "v1"
+        };
+    }
+    (reference.group.clone().unwrap_or_default(), None)
+}
+
+/// Resolve `reference` to an `ApiResource` via the discovery API
+///
+/// When `apiVersion` pins an exact version we resolve that version
+/// specifically - falling through to `recommended_kind`'s preferred/storage
+/// version would silently serve a different one than the caller asked for.
+/// Only a bare `group` (no version) goes through `recommended_kind`.
+async fn resolve_group(client: &Client, reference: &KReference) -> Result<ApiResource, Error> {
+    let (group_name, version) = group_and_version(reference);
+    let discovery = Discovery::new(client.clone()).run().await.map_err(Error::KubeError)?;
+    let group = discovery.groups().find(|g| g.name() == group_name).ok_or_else(|| {
+        Error::SinkNotResolvable(format!("could not resolve group \"{}\" via discovery", group_name))
+    })?;
+
+    let resolved = match &version {
+        Some(version) => group
+            .versioned_resources(version)
+            .into_iter()
+            .find(|(api_resource, _caps)| api_resource.kind == reference.kind),
+        None => group.recommended_kind(&reference.kind),
+    };
+
+    resolved.map(|(api_resource, _caps)| api_resource).ok_or_else(|| {
+        Error::SinkNotResolvable(format!(
+            "could not resolve group \"{}\" kind \"{}\"{} via discovery",
+            group_name,
+            reference.kind,
+            version.as_deref().map(|v| format!(" version \"{}\"", v)).unwrap_or_default()
+        ))
+    })
+}
+
+/// Join a possibly-relative `uri` onto the Addressable's base `url`
+fn join_uri(base: &str, uri: &str) -> Result<String, Error> {
+    reqwest::Url::parse(base)
+        .and_then(|base| base.join(uri))
+        .map(|url| url.to_string())
+        .map_err(|e| Error::SinkNotResolvable(format!("failed to join uri \"{}\" onto \"{}\": {}", uri, base, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(group: Option<&str>, api_version: Option<&str>) -> KReference {
+        KReference {
+            kind: "Channel".into(),
+            namespace: None,
+            name: "my-channel".into(),
+            api_version: api_version.map(str::to_string),
+            group: group.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn group_and_version_from_group_field_has_no_pinned_version() {
+        let (group, version) = group_and_version(&reference(Some("messaging.knative.dev"), None));
+        assert_eq!(group, "messaging.knative.dev");
+        assert_eq!(version, None, "a bare group should go through recommended_kind, not a pinned version");
+    }
+
+    #[test]
+    fn group_and_version_from_api_version_pins_the_version() {
+        let (group, version) = group_and_version(&reference(None, Some("messaging.knative.dev/v1beta1")));
+        assert_eq!(group, "messaging.knative.dev");
+        assert_eq!(version.as_deref(), Some("v1beta1"));
+    }
+
+    #[test]
+    fn group_and_version_from_core_api_version_is_the_empty_group() {
+        let (group, version) = group_and_version(&reference(None, Some("v1")));
+        assert_eq!(group, "", "a core apiVersion has no slash and resolves to the empty group");
+        assert_eq!(version.as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn absolute_uri_accepts_a_url_with_scheme_and_host() {
+        assert_eq!(absolute_uri("https://example.com/webhook").unwrap(), "https://example.com/webhook");
+    }
+
+    #[test]
+    fn absolute_uri_rejects_a_relative_path() {
+        assert!(absolute_uri("/webhook").is_err(), "a relative path has no scheme/host to stand on its own");
+    }
+
+    #[test]
+    fn absolute_uri_rejects_a_url_with_no_host() {
+        assert!(absolute_uri("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn join_uri_resolves_a_relative_path_against_the_base() {
+        let joined = join_uri("http://broker.svc.cluster.local/default", "/v1/events").unwrap();
+        assert_eq!(joined, "http://broker.svc.cluster.local/v1/events");
+    }
+
+    #[test]
+    fn join_uri_lets_an_absolute_uri_override_the_base() {
+        let joined = join_uri("http://broker.svc.cluster.local/default", "https://other.example.com/hook").unwrap();
+        assert_eq!(joined, "https://other.example.com/hook");
+    }
+}
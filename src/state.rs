@@ -1,91 +1,81 @@
-use log::{info, warn, error, debug, trace};
-use kubernetes::{
-    client::APIClient,
-    config::Configuration,
-    api::{Named, Cache, Reflector, ApiResource},
+use crate::Result;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use kube::{
+    api::{Api, ResourceExt},
+    runtime::{
+        reflector::{self, Store},
+        watcher, WatchStreamExt,
+    },
+    Client, CustomResource,
 };
-use std::{
-    env,
-    time::Duration,
-};
-use crate::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc};
+use tracing::{trace, warn};
 
 /// Approximation of the CRD we want to work with
 /// Replace with own struct.
-/// Add serialize for returnability.
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct FooResource {
-  name: String,
-  info: String,
-}
-impl Named for FooResource {
-    // we want Foo identified by self.name in the cache
-    fn name(&self) -> String {
-        self.name.clone()
-    }
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(kind = "Foo", group = "clux.dev", version = "v1", namespaced)]
+pub struct FooSpec {
+    info: String,
 }
 
 /// User state for Actix
 #[derive(Clone)]
 pub struct State {
     // Add resources you need in here, expose it as you see fit
-    // this example encapsulates it behind a getter and internal poll thread below.
-    foos: Reflector<FooResource>,
+    // this example encapsulates it behind a getter below, kept fresh by the
+    // watcher future returned alongside this `State` from `init`.
+    foos: Store<Foo>,
 }
 
-/// Example state machine that exposes the state of one `Reflector<FooResource>`
+/// Example state machine that exposes the state of one watched `Foo` kind
 ///
 /// This only deals with a single CRD, and it takes the NAMESPACE from an evar.
 impl State {
-    fn new(client: APIClient) -> Result<Self> {
-        let namespace = env::var("NAMESPACE").expect("Need NAMESPACE evar");
-        let fooresource = ApiResource {
-            group: "clux.dev".into(),
-            resource: "foos".into(),
-            namespace: namespace,
-        };
-        let foos = Reflector::new(client, fooresource)?;
-        Ok(State { foos })
-    }
-
-    /// Internal poll for internal thread
-    fn poll(&self) -> Result<()> {
-        self.foos.poll()
+    fn new(foos: Store<Foo>) -> Self {
+        State { foos }
     }
 
     /// Exposed refresh button for use by app
+    ///
+    /// The underlying `Store` is kept current by the watch stream, so there is
+    /// nothing to actively refresh any more; kept for API compatibility.
     pub fn refresh(&self) -> Result<()> {
-        self.foos.refresh()
+        Ok(())
     }
 
     /// Exposed getter for read access to state for app
-    pub fn foos(&self) -> Result<Cache<FooResource>> {
-        self.foos.read()
+    pub fn foos(&self) -> Vec<Arc<Foo>> {
+        self.foos.state()
     }
 }
 
 /// Lifecycle initialization interface for app
 ///
-/// This returns a `State` and calls `poll` on it continuously.
-/// As a result, this file encapsulates the only write access to a
-pub fn init(cfg: Configuration) -> Result<State> {
-    let client = APIClient::new(cfg);
-    let state = State::new(client)?; // for app to read
-    let state2 = state.clone(); // for internal thread to poll and update
-    std::thread::spawn(move || {
-        loop {
-            std::thread::sleep(Duration::from_secs(10));
-            // poll all reflectors here
-            // (this can cause a few more waits in edge cases)
-            match state2.poll() {
-                Ok(_) => trace!("State refreshed"),
-                Err(e) => {
-                    // Bad fallback, but at least it leaves system working.
-                    error!("Failed to refesh cache '{}' - rebooting", e);
-                    std::process::exit(1);
-                }
+/// This returns a `State` backed by a `watcher`-fed `Store`, plus the future
+/// that drives the watch. The watch uses resourceVersion bookmarks and
+/// re-lists on `410 Gone` / desync on its own, so callers no longer need to
+/// poll or exit the process on error - just keep the returned future running.
+pub fn init(client: Client) -> Result<(State, BoxFuture<'static, ()>)> {
+    let namespace = env::var("NAMESPACE").expect("Need NAMESPACE evar");
+    let foos: Api<Foo> = Api::namespaced(client, &namespace);
+    let (reader, writer) = reflector::store();
+    let state = State::new(reader);
+
+    let drainer = watcher(foos, watcher::Config::default())
+        .default_backoff()
+        .reflect(writer)
+        .applied_objects()
+        .for_each(|res| {
+            match res {
+                Ok(o) => trace!("Foo \"{}\" updated", o.name_any()),
+                Err(e) => warn!("Foo watch stream error: {}", e),
             }
-        }
-    });
-    Ok(state)
-}
\ No newline at end of file
+            futures::future::ready(())
+        })
+        .boxed();
+
+    Ok((state, drainer))
+}